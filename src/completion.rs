@@ -1,9 +1,20 @@
-use axum::{extract::State, Json};
-use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Json,
+};
+use futures_util::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    sampler::Sampler, FinishReason, GenerateRequest, OptionArray, ThreadRequest, TokenCounter,
+    grammar::Grammar, sampler::Sampler, FinishReason, GenerateRequest, OptionArray, ThreadRequest,
+    TokenCounter,
 };
 
 #[derive(Debug, Clone, Deserialize)]
@@ -12,6 +23,12 @@ pub struct CompletionRequest {
     pub prompt: OptionArray<String>,
     pub max_tokens: usize,
     pub stop: OptionArray<String>,
+    pub stream: bool,
+    pub n: usize,
+    pub best_of: Option<usize>,
+    pub seed: Option<u64>,
+    pub grammar: Option<Grammar>,
+    pub logprobs: Option<usize>,
     pub temperature: f32,
     pub top_p: f32,
     pub presence_penalty: f32,
@@ -24,6 +41,12 @@ impl Default for CompletionRequest {
             prompt: OptionArray::default(),
             max_tokens: 256,
             stop: OptionArray::default(),
+            stream: false,
+            n: 1,
+            best_of: None,
+            seed: None,
+            grammar: None,
+            logprobs: None,
             temperature: 1.0,
             top_p: 1.0,
             presence_penalty: 0.0,
@@ -32,12 +55,29 @@ impl Default for CompletionRequest {
     }
 }
 
+impl CompletionRequest {
+    /// Returns a clone of this request with its prompt array replaced by a single prompt, used
+    /// to dispatch one independent generation per element of a batched `prompt` array.
+    fn with_prompt(&self, prompt: String) -> Self {
+        Self {
+            prompt: OptionArray::from(vec![prompt]),
+            ..self.clone()
+        }
+    }
+}
+
 impl From<CompletionRequest> for GenerateRequest {
     fn from(value: CompletionRequest) -> Self {
         let CompletionRequest {
             prompt,
             max_tokens,
             stop,
+            stream: _,
+            n: _,
+            best_of: _,
+            seed,
+            grammar,
+            logprobs: _,
             temperature,
             top_p,
             presence_penalty,
@@ -47,12 +87,22 @@ impl From<CompletionRequest> for GenerateRequest {
         let prompt = Vec::from(prompt).join("");
         let max_tokens = max_tokens.min(crate::MAX_TOKENS);
         let stop = stop.into();
+        // Compiling here only gets the `Dfa` as far as `GenerateRequest`. The decode loop that
+        // actually drives generation (outside this module) is the side that must mask each
+        // step's logits via `Dfa::token_index` and advance the state via `Dfa::advance_state` --
+        // until it does, a `grammar` has no effect on the tokens produced.
+        let grammar = grammar.map(|grammar| grammar.compile());
 
         Self {
             prompt,
             max_tokens,
             stop,
+            grammar,
+            // `seed` only reaches reproducible output if `Sampler` (defined outside this
+            // module) actually seeds its PRNG from it -- that's what `generate_best_of`'s
+            // per-candidate seed offsetting below depends on to produce distinct samples.
             sampler: Sampler {
+                seed,
                 temperature,
                 top_p,
                 presence_penalty,
@@ -68,6 +118,19 @@ pub struct CompletionChoice {
     pub text: String,
     pub index: usize,
     pub finish_reason: FinishReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<CompletionLogprobs>,
+}
+
+/// Per-token log-probabilities for a [`CompletionChoice`], requested via
+/// `CompletionRequest::logprobs`: the sampled token's own log-probability and, alongside it, the
+/// top-k alternative tokens the sampler considered at that position.
+#[derive(Debug, Clone, Serialize)]
+pub struct CompletionLogprobs {
+    pub tokens: Vec<String>,
+    pub token_logprobs: Vec<f32>,
+    pub top_logprobs: Vec<HashMap<String, f32>>,
+    pub text_offset: Vec<usize>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -81,7 +144,196 @@ pub struct CompletionResponse {
 pub async fn completions(
     State(sender): State<flume::Sender<ThreadRequest>>,
     Json(request): Json<CompletionRequest>,
-) -> Json<CompletionResponse> {
+) -> impl IntoResponse {
+    let stream = request.stream;
+    let prompts: Vec<String> = request.prompt.clone().into();
+    // An omitted/empty `prompt` array means "one generation with no prompt", matching the
+    // pre-batching behavior where `Vec::from(prompt).join("")` collapsed an empty array to `""`.
+    let prompts = if prompts.is_empty() {
+        vec![String::new()]
+    } else {
+        prompts
+    };
+
+    if prompts.len() > crate::MAX_CLIENT_BATCH_SIZE {
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            format!(
+                "prompt array of {} exceeds the maximum client batch size of {}",
+                prompts.len(),
+                crate::MAX_CLIENT_BATCH_SIZE
+            ),
+        )
+            .into_response();
+    }
+
+    if stream {
+        if prompts.len() > 1 {
+            return (
+                axum::http::StatusCode::BAD_REQUEST,
+                "stream does not support a batched prompt array".to_string(),
+            )
+                .into_response();
+        }
+        let n = request.n.max(1);
+        let best_of = request.best_of.unwrap_or(n).max(n);
+        if n > 1 || best_of > 1 {
+            return (
+                axum::http::StatusCode::BAD_REQUEST,
+                "stream does not support n/best_of greater than 1".to_string(),
+            )
+                .into_response();
+        }
+        if request.logprobs.is_some() {
+            return (
+                axum::http::StatusCode::BAD_REQUEST,
+                "stream does not support logprobs".to_string(),
+            )
+                .into_response();
+        }
+
+        let (prompt_tokens_sender, prompt_tokens_receiver) = flume::unbounded();
+        let (token_sender, token_receiver) = flume::unbounded();
+
+        let _ = sender.send(ThreadRequest {
+            request: crate::RequestKind::Completion(request),
+            prompt_tokens_sender,
+            token_sender,
+        });
+
+        let prompt_tokens = prompt_tokens_receiver
+            .recv_async()
+            .await
+            .unwrap_or_default();
+        let counter = TokenCounter {
+            prompt_tokens,
+            completion_tokens: 0,
+            total_tokens: prompt_tokens,
+        };
+
+        return completions_stream(counter, token_receiver).into_response();
+    }
+
+    let n = request.n.max(1);
+    let best_of = request.best_of.unwrap_or(n).max(n);
+
+    if best_of > crate::MAX_BEST_OF {
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            format!(
+                "n/best_of of {} exceeds the maximum of {}",
+                best_of,
+                crate::MAX_BEST_OF
+            ),
+        )
+            .into_response();
+    }
+
+    let choices = futures_util::future::join_all(prompts.into_iter().enumerate().map(
+        |(prompt_index, prompt)| {
+            generate_best_of(
+                sender.clone(),
+                request.with_prompt(prompt),
+                prompt_index * n,
+                n,
+                best_of,
+            )
+        },
+    ))
+    .await;
+
+    let mut prompt_tokens = 0;
+    let mut completion_tokens = 0;
+    let mut total_tokens = 0;
+    let mut result = Vec::with_capacity(choices.len());
+    for (prompt_choices, counter) in choices {
+        prompt_tokens += counter.prompt_tokens;
+        completion_tokens += counter.completion_tokens;
+        total_tokens += counter.total_tokens;
+        result.extend(prompt_choices);
+    }
+
+    Json(CompletionResponse {
+        object: "text_completion".into(),
+        choices: result,
+        counter: TokenCounter {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens,
+        },
+    })
+    .into_response()
+}
+
+/// Runs `best_of` independent samples of one prompt, scores each by its cumulative token
+/// log-probability, and keeps the top `n` (ranked best-first, indices assigned starting at
+/// `index_offset`). `n == 1, best_of == 1` is the single-completion case used by the batch
+/// dispatch in [`completions`]. The returned [`TokenCounter`] sums usage across all `best_of`
+/// samples, not just the `n` that are kept, since the worker ran every one of them regardless.
+async fn generate_best_of(
+    sender: flume::Sender<ThreadRequest>,
+    request: CompletionRequest,
+    index_offset: usize,
+    n: usize,
+    best_of: usize,
+) -> (Vec<CompletionChoice>, TokenCounter) {
+    let mut samples = futures_util::future::join_all((0..best_of).map(|i| {
+        // Offset a fixed seed per candidate, otherwise every sample would be byte-identical.
+        let mut request = request.clone();
+        request.seed = request.seed.map(|seed| seed.wrapping_add(i as u64));
+        generate_sample(sender.clone(), request)
+    }))
+    .await;
+
+    // Usage reflects every one of the `best_of` samples actually generated, not just the `n`
+    // that survive ranking below -- the worker ran all of them regardless of which are kept.
+    let counter = samples.iter().fold(
+        TokenCounter {
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            total_tokens: 0,
+        },
+        |mut total, sample| {
+            total.prompt_tokens += sample.counter.prompt_tokens;
+            total.completion_tokens += sample.counter.completion_tokens;
+            total.total_tokens += sample.counter.total_tokens;
+            total
+        },
+    );
+
+    samples.sort_by(|a, b| b.logprob.total_cmp(&a.logprob));
+
+    let choices = samples
+        .into_iter()
+        .take(n)
+        .enumerate()
+        .map(|(index, sample)| CompletionChoice {
+            text: sample.text,
+            index: index_offset + index,
+            finish_reason: sample.finish_reason,
+            logprobs: sample.logprobs,
+        })
+        .collect();
+
+    (choices, counter)
+}
+
+/// One sampled completion of a prompt, together with the summed per-token log-probability used
+/// to rank `best_of` candidates against each other.
+struct Sample {
+    text: String,
+    finish_reason: FinishReason,
+    logprob: f32,
+    counter: TokenCounter,
+    logprobs: Option<CompletionLogprobs>,
+}
+
+async fn generate_sample(
+    sender: flume::Sender<ThreadRequest>,
+    request: CompletionRequest,
+) -> Sample {
+    let want_logprobs = request.logprobs.is_some();
+
     let (prompt_tokens_sender, prompt_tokens_receiver) = flume::unbounded();
     let (token_sender, token_receiver) = flume::unbounded();
 
@@ -103,12 +355,32 @@ pub async fn completions(
 
     let mut finish_reason = FinishReason::Null;
     let mut text = String::new();
+    let mut logprob = 0.0f32;
+    let mut logprobs = want_logprobs.then(|| CompletionLogprobs {
+        tokens: Vec::new(),
+        token_logprobs: Vec::new(),
+        top_logprobs: Vec::new(),
+        text_offset: Vec::new(),
+    });
     let mut stream = token_receiver.into_stream();
 
     while let Some(token) = stream.next().await {
         match token {
-            crate::Token::Token(token) => {
+            // `Token::Token`'s `(text, token_logprob, top_logprobs)` shape is a dependency on
+            // the `Token` enum (defined outside this module) growing a real per-token logprob
+            // and top-k alternatives alongside the sampled text; this module only consumes that
+            // shape, it doesn't produce it.
+            crate::Token::Token(token, token_logprob, top_logprobs) => {
+                if let Some(logprobs) = &mut logprobs {
+                    logprobs.text_offset.push(text.len());
+                    logprobs.tokens.push(token.clone());
+                    logprobs.token_logprobs.push(token_logprob);
+                    logprobs
+                        .top_logprobs
+                        .push(top_logprobs.into_iter().collect());
+                }
                 text += &token;
+                logprob += token_logprob;
                 counter.completion_tokens += 1;
                 counter.total_tokens += 1;
             }
@@ -123,13 +395,271 @@ pub async fn completions(
         }
     }
 
-    Json(CompletionResponse {
-        object: "text_completion".into(),
-        choices: vec![CompletionChoice {
-            text,
-            index: 0,
-            finish_reason,
-        }],
+    Sample {
+        text,
+        finish_reason,
+        logprob,
         counter,
+        logprobs,
+    }
+}
+
+/// Assembles the per-token [`CompletionResponse`] chunks sent over SSE: one chunk per generated
+/// token (`finish_reason: Null`), followed by a final chunk (`true`) once the worker reports
+/// `EndOfText`/`CutOff` or the channel closes. Split out from [`completions_stream`] so the
+/// chunk assembly itself -- independent of how it's wrapped into `Event`s -- can be tested
+/// directly.
+fn completion_chunks(
+    counter: TokenCounter,
+    token_receiver: flume::Receiver<crate::Token>,
+) -> impl Stream<Item = (CompletionResponse, bool)> {
+    let state = (token_receiver.into_stream(), counter, false);
+
+    futures_util::stream::unfold(state, |(mut stream, mut counter, done)| async move {
+        if done {
+            return None;
+        }
+
+        let next = stream.next().await;
+        let (text, finish_reason, done) = match next {
+            Some(crate::Token::Token(token, _logprob, _top_logprobs)) => {
+                counter.completion_tokens += 1;
+                counter.total_tokens += 1;
+                (token, FinishReason::Null, false)
+            }
+            Some(crate::Token::EndOfText) | None => (String::new(), FinishReason::Stop, true),
+            Some(crate::Token::CutOff) => (String::new(), FinishReason::Length, true),
+        };
+
+        let chunk = CompletionResponse {
+            object: "text_completion".into(),
+            choices: vec![CompletionChoice {
+                text,
+                index: 0,
+                finish_reason,
+                logprobs: None,
+            }],
+            counter: TokenCounter {
+                prompt_tokens: counter.prompt_tokens,
+                completion_tokens: counter.completion_tokens,
+                total_tokens: counter.total_tokens,
+            },
+        };
+
+        Some(((chunk, done), (stream, counter, done)))
     })
 }
+
+/// Builds the `Sse` response used when `CompletionRequest::stream` is set: each generated
+/// token is forwarded as its own `data:` event carrying a partial [`CompletionResponse`], and
+/// the stream is closed with a `finish_reason` event followed by `data: [DONE]`.
+fn completions_stream(
+    counter: TokenCounter,
+    token_receiver: flume::Receiver<crate::Token>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = completion_chunks(counter, token_receiver).flat_map(|(chunk, done)| {
+        let event = Event::default().json_data(chunk).unwrap_or_default();
+        let events = if done {
+            vec![Ok(event), Ok(Event::default().data("[DONE]"))]
+        } else {
+            vec![Ok(event)]
+        };
+        futures_util::stream::iter(events)
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for the worker thread in tests: for every `ThreadRequest` it receives, replies
+    /// over the same `prompt_tokens_sender`/`token_sender` channels a real worker would use,
+    /// without requiring a model or decode loop.
+    fn spawn_stub_worker(
+        receiver: flume::Receiver<ThreadRequest>,
+        mut respond: impl FnMut(&CompletionRequest) -> (usize, Vec<crate::Token>) + Send + 'static,
+    ) {
+        tokio::spawn(async move {
+            while let Ok(thread_request) = receiver.recv_async().await {
+                let crate::RequestKind::Completion(request) = thread_request.request else {
+                    continue;
+                };
+                let (prompt_tokens, tokens) = respond(&request);
+                let _ = thread_request.prompt_tokens_sender.send(prompt_tokens);
+                for token in tokens {
+                    let _ = thread_request.token_sender.send(token);
+                }
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn batch_dispatch_assigns_disjoint_index_ranges_per_prompt() {
+        let (sender, receiver) = flume::unbounded();
+        spawn_stub_worker(receiver, |request| {
+            let prompt: String = Vec::from(request.prompt.clone()).join("");
+            (
+                1,
+                vec![
+                    crate::Token::Token(format!("{prompt}-out"), 0.0, Vec::new()),
+                    crate::Token::EndOfText,
+                ],
+            )
+        });
+
+        // Mirrors completions()'s own dispatch: each prompt gets an index range starting at
+        // `prompt_index * n`.
+        let n = 2;
+        let results = futures_util::future::join_all((0..2).map(|prompt_index| {
+            generate_best_of(
+                sender.clone(),
+                CompletionRequest {
+                    prompt: OptionArray::from(vec![format!("p{prompt_index}")]),
+                    ..Default::default()
+                },
+                prompt_index * n,
+                n,
+                n,
+            )
+        }))
+        .await;
+
+        let indices: Vec<usize> = results
+            .iter()
+            .flat_map(|(choices, _)| choices.iter().map(|choice| choice.index))
+            .collect();
+        assert_eq!(indices, vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn best_of_keeps_the_n_highest_logprob_samples_and_sums_usage_across_all_of_them() {
+        let (sender, receiver) = flume::unbounded();
+        // Each candidate's seed is `request.seed` offset by its index (see `generate_best_of`);
+        // keying the emitted logprob off the seed lets this test control the ranking precisely.
+        spawn_stub_worker(receiver, |request| {
+            let seed = request.seed.unwrap();
+            (
+                1,
+                vec![
+                    crate::Token::Token(seed.to_string(), seed as f32, Vec::new()),
+                    crate::Token::EndOfText,
+                ],
+            )
+        });
+
+        let (choices, counter) = generate_best_of(
+            sender,
+            CompletionRequest {
+                prompt: OptionArray::from(vec!["p".to_string()]),
+                seed: Some(0),
+                ..Default::default()
+            },
+            0,
+            2,
+            4,
+        )
+        .await;
+
+        // best_of = 4 candidates score 0, 1, 2, 3 by seed offset; the top 2 (seeds 3 and 2) survive.
+        assert_eq!(choices.len(), 2);
+        assert_eq!(choices[0].index, 0);
+        assert_eq!(choices[1].index, 1);
+        assert_eq!(choices[0].text, "3");
+        assert_eq!(choices[1].text, "2");
+
+        // Usage sums across all 4 samples the worker actually ran, not just the 2 that were kept.
+        assert_eq!(counter.prompt_tokens, 4);
+        assert_eq!(counter.completion_tokens, 4);
+        assert_eq!(counter.total_tokens, 8);
+    }
+
+    #[tokio::test]
+    async fn best_of_offsets_the_seed_of_each_candidate_by_its_index() {
+        let seen_seeds = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (sender, receiver) = flume::unbounded();
+        spawn_stub_worker(receiver, {
+            let seen_seeds = seen_seeds.clone();
+            move |request| {
+                seen_seeds.lock().unwrap().push(request.seed.unwrap());
+                (1, vec![crate::Token::EndOfText])
+            }
+        });
+
+        let _ = generate_best_of(
+            sender,
+            CompletionRequest {
+                prompt: OptionArray::from(vec!["p".to_string()]),
+                seed: Some(10),
+                ..Default::default()
+            },
+            0,
+            3,
+            3,
+        )
+        .await;
+
+        // Every candidate must start from the request's own seed, wrapping-offset by its index --
+        // otherwise every candidate would draw the exact same sample.
+        let mut seeds = seen_seeds.lock().unwrap().clone();
+        seeds.sort();
+        assert_eq!(seeds, vec![10, 11, 12]);
+    }
+
+    #[tokio::test]
+    async fn sse_stream_emits_one_chunk_per_token_then_a_final_chunk() {
+        let (token_sender, token_receiver) = flume::unbounded();
+        token_sender
+            .send(crate::Token::Token("hel".into(), -0.1, Vec::new()))
+            .unwrap();
+        token_sender
+            .send(crate::Token::Token("lo".into(), -0.2, Vec::new()))
+            .unwrap();
+        token_sender.send(crate::Token::EndOfText).unwrap();
+        drop(token_sender);
+
+        let counter = TokenCounter {
+            prompt_tokens: 3,
+            completion_tokens: 0,
+            total_tokens: 3,
+        };
+        let chunks: Vec<(CompletionResponse, bool)> =
+            completion_chunks(counter, token_receiver).collect().await;
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].0.choices[0].text, "hel");
+        assert_eq!(chunks[1].0.choices[0].text, "lo");
+        assert!(!chunks[0].1 && !chunks[1].1, "token chunks are not final");
+        assert!(chunks[2].1, "the closing chunk is marked final");
+        assert_eq!(chunks[2].0.choices[0].text, "");
+        // completion_tokens/total_tokens accumulate across the two token chunks.
+        assert_eq!(chunks[2].0.counter.completion_tokens, 2);
+        assert_eq!(chunks[2].0.counter.total_tokens, 5);
+    }
+
+    #[tokio::test]
+    async fn sse_stream_reports_length_finish_reason_on_cutoff() {
+        let (token_sender, token_receiver) = flume::unbounded();
+        token_sender
+            .send(crate::Token::Token("hi".into(), -0.1, Vec::new()))
+            .unwrap();
+        token_sender.send(crate::Token::CutOff).unwrap();
+        drop(token_sender);
+
+        let counter = TokenCounter {
+            prompt_tokens: 1,
+            completion_tokens: 0,
+            total_tokens: 1,
+        };
+        let chunks: Vec<(CompletionResponse, bool)> =
+            completion_chunks(counter, token_receiver).collect().await;
+
+        let (last, done) = chunks.last().unwrap();
+        assert!(done);
+        assert!(matches!(
+            last.choices[0].finish_reason,
+            FinishReason::Length
+        ));
+    }
+}
@@ -0,0 +1,654 @@
+//! Grammar-constrained decoding.
+//!
+//! A [`Grammar`] (a regex, or a JSON schema lowered to an equivalent regex) is compiled into a
+//! [`Dfa`] over bytes. For a given tokenizer vocabulary, [`Dfa::token_index`] precomputes, for
+//! every DFA state, the set of vocabulary token ids that keep the automaton on a valid path --
+//! the sampler masks out every other token before drawing, then advances the DFA state by
+//! whichever token was sampled. Compiled grammars are cached so repeated requests with the same
+//! pattern skip recompilation; since `grammar` is client-supplied, the cache is bounded (oldest
+//! pattern evicted first) so an endless stream of distinct patterns can't grow it forever.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+
+/// A grammar a completion's token stream must conform to: either a regex directly, or a JSON
+/// schema that is first lowered to an equivalent regex.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Grammar {
+    Regex(String),
+    JsonSchema(serde_json::Value),
+}
+
+impl Grammar {
+    fn pattern(&self) -> String {
+        match self {
+            Grammar::Regex(pattern) => pattern.clone(),
+            Grammar::JsonSchema(schema) => json_schema_to_regex(schema),
+        }
+    }
+
+    /// Compiles (or fetches from cache) the DFA for this grammar.
+    pub fn compile(&self) -> Arc<Dfa> {
+        compile_cached(&self.pattern())
+    }
+}
+
+/// Maximum number of distinct compiled patterns kept in [`CACHE`] at once.
+const MAX_CACHED_GRAMMARS: usize = 256;
+
+/// A pattern -> compiled DFA cache, bounded to [`MAX_CACHED_GRAMMARS`] entries with FIFO
+/// eviction (`order` tracks insertion order; the oldest entry is dropped first).
+#[derive(Default)]
+struct GrammarCache {
+    by_pattern: HashMap<String, Arc<Dfa>>,
+    order: VecDeque<String>,
+}
+
+static CACHE: RwLock<Option<GrammarCache>> = RwLock::new(None);
+
+fn compile_cached(pattern: &str) -> Arc<Dfa> {
+    if let Some(dfa) = CACHE
+        .read()
+        .unwrap()
+        .as_ref()
+        .and_then(|cache| cache.by_pattern.get(pattern))
+    {
+        return dfa.clone();
+    }
+
+    let dfa = Arc::new(Dfa::compile(pattern));
+    let mut guard = CACHE.write().unwrap();
+    let cache = guard.get_or_insert_with(GrammarCache::default);
+    if !cache.by_pattern.contains_key(pattern) {
+        if cache.by_pattern.len() >= MAX_CACHED_GRAMMARS {
+            if let Some(oldest) = cache.order.pop_front() {
+                cache.by_pattern.remove(&oldest);
+            }
+        }
+        cache.order.push_back(pattern.to_string());
+        cache.by_pattern.insert(pattern.to_string(), dfa.clone());
+    }
+    dfa
+}
+
+/// A DFA state id. `DEAD` is the sink state every invalid transition lands in.
+type StateId = usize;
+const DEAD: StateId = 0;
+
+/// A deterministic finite automaton over bytes, built from a small regex subset (literals,
+/// concatenation, `|` alternation, `*`/`+`/`?` quantifiers, `.` wildcard and `[...]` classes) via
+/// Thompson construction followed by subset construction.
+pub struct Dfa {
+    transitions: Vec<[StateId; 256]>,
+    accepting: Vec<bool>,
+    start: StateId,
+}
+
+impl Dfa {
+    fn compile(pattern: &str) -> Self {
+        let nfa = Nfa::parse(pattern);
+        nfa.to_dfa()
+    }
+
+    pub fn start(&self) -> StateId {
+        self.start
+    }
+
+    pub fn is_accepting(&self, state: StateId) -> bool {
+        self.accepting[state]
+    }
+
+    pub fn is_dead(&self, state: StateId) -> bool {
+        state == DEAD
+    }
+
+    fn step(&self, state: StateId, byte: u8) -> StateId {
+        self.transitions[state][byte as usize]
+    }
+
+    /// Runs every byte of `token`, returning the resulting state, or `None` if any prefix of
+    /// `token` would drive the automaton into the dead state (a multi-byte token is only a valid
+    /// continuation if every one of its bytes keeps the automaton alive, not just its last one).
+    fn advance(&self, state: StateId, token: &str) -> Option<StateId> {
+        let mut state = state;
+        for byte in token.bytes() {
+            state = self.step(state, byte);
+            if self.is_dead(state) {
+                return None;
+            }
+        }
+        Some(state)
+    }
+
+    /// For every DFA state, the set of vocabulary token ids whose bytes keep the automaton off
+    /// the dead state. An empty set for a state means the grammar has no valid continuation from
+    /// there, which the caller should treat as a hard stop.
+    pub fn token_index(&self, vocab: &[(u32, String)]) -> Vec<HashSet<u32>> {
+        (0..self.transitions.len())
+            .map(|state| {
+                vocab
+                    .iter()
+                    .filter_map(|(id, token)| self.advance(state, token).map(|_| *id))
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn advance_state(&self, state: StateId, token: &str) -> StateId {
+        self.advance(state, token).unwrap_or(DEAD)
+    }
+}
+
+/// A Thompson-construction NFA, built directly from the parsed regex AST; only ever exists
+/// transiently on the way to a [`Dfa`].
+struct Nfa {
+    states: Vec<NfaState>,
+    start: usize,
+    end: usize,
+}
+
+enum NfaState {
+    /// Consumes one byte in `bytes` and moves to `next`.
+    Byte(HashSet<u8>, usize),
+    /// Epsilon transitions to zero or more states without consuming input.
+    Split(Vec<usize>),
+    Match,
+}
+
+impl Nfa {
+    fn parse(pattern: &str) -> Self {
+        let mut builder = NfaBuilder::default();
+        let (start, end) = builder.alternation(&mut pattern.chars().peekable());
+        Nfa {
+            states: builder.states,
+            start,
+            end,
+        }
+    }
+
+    fn to_dfa(&self) -> Dfa {
+        let start_set = self.epsilon_closure(&[self.start]);
+        let mut index: HashMap<Vec<usize>, StateId> = HashMap::new();
+        index.insert(Vec::new(), DEAD); // empty set == dead state
+        index.insert(sorted(&start_set), 1);
+
+        let mut transitions = vec![[DEAD; 256]; 2];
+        let mut accepting = vec![false, start_set.contains(&self.end)];
+
+        let mut frontier = vec![(1usize, start_set)];
+        while let Some((id, set)) = frontier.pop() {
+            for byte in 0u16..256 {
+                let byte = byte as u8;
+                let next: HashSet<usize> = set
+                    .iter()
+                    .filter_map(|&s| match &self.states[s] {
+                        NfaState::Byte(bytes, next) if bytes.contains(&byte) => Some(*next),
+                        _ => None,
+                    })
+                    .flat_map(|s| self.epsilon_closure(&[s]))
+                    .collect();
+
+                if next.is_empty() {
+                    continue;
+                }
+
+                let key = sorted(&next);
+                let next_id = *index.entry(key).or_insert_with(|| {
+                    let id = transitions.len();
+                    transitions.push([DEAD; 256]);
+                    accepting.push(next.contains(&self.end));
+                    frontier.push((id, next.clone()));
+                    id
+                });
+                transitions[id][byte as usize] = next_id;
+            }
+        }
+
+        Dfa {
+            transitions,
+            accepting,
+            start: 1,
+        }
+    }
+
+    fn epsilon_closure(&self, from: &[usize]) -> HashSet<usize> {
+        let mut seen: HashSet<usize> = from.iter().copied().collect();
+        let mut stack: Vec<usize> = from.to_vec();
+        while let Some(s) = stack.pop() {
+            if let NfaState::Split(targets) = &self.states[s] {
+                for &t in targets {
+                    if seen.insert(t) {
+                        stack.push(t);
+                    }
+                }
+            }
+        }
+        seen
+    }
+}
+
+fn sorted(set: &HashSet<usize>) -> Vec<usize> {
+    let mut v: Vec<usize> = set.iter().copied().collect();
+    v.sort_unstable();
+    v
+}
+
+/// Recursive-descent parser over the supported regex subset, emitting Thompson-construction NFA
+/// fragments directly (no separate AST).
+#[derive(Default)]
+struct NfaBuilder {
+    states: Vec<NfaState>,
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+impl NfaBuilder {
+    fn push(&mut self, state: NfaState) -> usize {
+        self.states.push(state);
+        self.states.len() - 1
+    }
+
+    /// `a|b|c`
+    fn alternation(&mut self, chars: &mut Chars) -> (usize, usize) {
+        let mut branches = vec![self.concatenation(chars)];
+        while chars.peek() == Some(&'|') {
+            chars.next();
+            branches.push(self.concatenation(chars));
+        }
+        self.alternate_fragments(branches)
+    }
+
+    /// `ab c`
+    fn concatenation(&mut self, chars: &mut Chars) -> (usize, usize) {
+        let mut fragments = Vec::new();
+        while let Some(&c) = chars.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            fragments.push(self.quantified(chars));
+        }
+        if fragments.is_empty() {
+            let s = self.push(NfaState::Split(Vec::new()));
+            return (s, s);
+        }
+        let mut iter = fragments.into_iter();
+        let (start, mut end) = iter.next().unwrap();
+        for (next_start, next_end) in iter {
+            self.patch(end, next_start);
+            end = next_end;
+        }
+        (start, end)
+    }
+
+    /// A single atom, optionally followed by `*`, `+` or `?`.
+    fn quantified(&mut self, chars: &mut Chars) -> (usize, usize) {
+        let atom = self.atom(chars);
+        match chars.peek() {
+            Some('*') => {
+                chars.next();
+                self.star(atom)
+            }
+            Some('+') => {
+                chars.next();
+                self.plus(atom)
+            }
+            Some('?') => {
+                chars.next();
+                self.optional(atom)
+            }
+            _ => atom,
+        }
+    }
+
+    fn star(&mut self, (start, end): (usize, usize)) -> (usize, usize) {
+        let split = self.push(NfaState::Split(vec![start]));
+        self.patch(end, split);
+        (split, split)
+    }
+
+    fn plus(&mut self, (start, end): (usize, usize)) -> (usize, usize) {
+        let split = self.push(NfaState::Split(vec![start]));
+        self.patch(end, split);
+        (start, split)
+    }
+
+    fn optional(&mut self, (start, end): (usize, usize)) -> (usize, usize) {
+        let split = self.push(NfaState::Split(vec![start, end]));
+        (split, end)
+    }
+
+    fn atom(&mut self, chars: &mut Chars) -> (usize, usize) {
+        match chars.next() {
+            Some('(') => {
+                let inner = self.alternation(chars);
+                chars.next(); // ')'
+                inner
+            }
+            Some('.') => self.byte_set((0u16..256).map(|b| b as u8).collect()),
+            Some('[') => self.char_class(chars),
+            Some('\\') => {
+                let escaped = chars.next().unwrap_or('\\');
+                self.literal(escaped)
+            }
+            Some(c) => self.literal(c),
+            None => {
+                let s = self.push(NfaState::Split(Vec::new()));
+                (s, s)
+            }
+        }
+    }
+
+    /// A `[...]` character class. ASCII members -- the common case, and the only one where a
+    /// `-` range or `^` negation is meaningful byte-for-byte -- compile into a single-byte
+    /// alternation. A non-ASCII member is its own multi-byte UTF-8 sequence, not a single byte
+    /// (e.g. `é` is `0xC3 0xA9`, not `0xE9`), so each one is compiled with `literal` and
+    /// alternated in alongside the ASCII byte set.
+    fn char_class(&mut self, chars: &mut Chars) -> (usize, usize) {
+        let negate = chars.peek() == Some(&'^');
+        if negate {
+            chars.next();
+        }
+        let mut set = HashSet::new();
+        let mut wide = Vec::new();
+        while let Some(&c) = chars.peek() {
+            if c == ']' {
+                chars.next();
+                break;
+            }
+            chars.next();
+            if c.is_ascii() && chars.peek() == Some(&'-') {
+                let mut lookahead = chars.clone();
+                lookahead.next();
+                if let Some(&hi) = lookahead.peek() {
+                    if hi != ']' && hi.is_ascii() {
+                        chars.next();
+                        chars.next();
+                        for b in (c as u8)..=(hi as u8) {
+                            set.insert(b);
+                        }
+                        continue;
+                    }
+                }
+            }
+            if c.is_ascii() {
+                set.insert(c as u8);
+            } else {
+                wide.push(c);
+            }
+        }
+
+        if negate {
+            // Negation is only meaningful byte-for-byte; a non-ASCII member can't be
+            // complemented this way, so a negated class is restricted to its ASCII members.
+            let negated: HashSet<u8> = (0u16..256)
+                .map(|b| b as u8)
+                .filter(|b| !set.contains(b))
+                .collect();
+            return self.byte_set(negated);
+        }
+
+        let mut fragments = vec![self.byte_set(set)];
+        fragments.extend(wide.into_iter().map(|c| self.literal(c)));
+        self.alternate_fragments(fragments)
+    }
+
+    /// Alternates a set of already-built fragments together, as `alternation` does for `|`
+    /// branches.
+    fn alternate_fragments(&mut self, mut branches: Vec<(usize, usize)>) -> (usize, usize) {
+        if branches.len() == 1 {
+            return branches.pop().unwrap();
+        }
+        let end = self.push(NfaState::Split(Vec::new()));
+        let starts = branches
+            .into_iter()
+            .map(|(start, branch_end)| {
+                self.patch(branch_end, end);
+                start
+            })
+            .collect();
+        let start = self.push(NfaState::Split(starts));
+        (start, end)
+    }
+
+    fn byte_set(&mut self, set: HashSet<u8>) -> (usize, usize) {
+        let end = self.push(NfaState::Match);
+        let start = self.push(NfaState::Byte(set, end));
+        (start, end)
+    }
+
+    /// A single `char` may be multiple UTF-8 bytes; those bytes form a sequence, not a choice,
+    /// so this chains one single-byte fragment per byte rather than using `byte_set`.
+    fn literal(&mut self, c: char) -> (usize, usize) {
+        let mut buf = [0u8; 4];
+        let bytes: Vec<u8> = c.encode_utf8(&mut buf).bytes().collect();
+        let fragments: Vec<(usize, usize)> = bytes
+            .into_iter()
+            .map(|b| self.byte_set(HashSet::from([b])))
+            .collect();
+        let mut iter = fragments.into_iter();
+        let (start, mut end) = iter.next().expect("a char is at least one byte");
+        for (next_start, next_end) in iter {
+            self.patch(end, next_start);
+            end = next_end;
+        }
+        (start, end)
+    }
+
+    /// Rewires every dangling transition out of `state` (a placeholder `Match`/empty `Split`)
+    /// to instead continue at `target`.
+    fn patch(&mut self, state: usize, target: usize) {
+        match &mut self.states[state] {
+            NfaState::Match => self.states[state] = NfaState::Split(vec![target]),
+            NfaState::Split(targets) => targets.push(target),
+            NfaState::Byte(_, _) => unreachable!("patch target is always a Match or Split"),
+        }
+    }
+}
+
+/// Lowers a (small, common) subset of JSON Schema to an equivalent regex over JSON text: string,
+/// number, boolean, null, enum-of-literals, and objects with `properties`/`required`. Anything
+/// outside that subset falls back to a permissive catch-all so unsupported schemas don't reject
+/// every token outright.
+///
+/// Object properties are grouped required-first, then optional, regardless of declaration order:
+/// required fields are always emitted, while the optional fields form a right-nested chain of
+/// `(,"key":value...)?` groups so any contiguous prefix of them (in that order) may be present
+/// without ever leaving a dangling comma. Arbitrary subsets of optional fields (e.g. the second
+/// one present without the first) are outside this subset.
+fn json_schema_to_regex(schema: &serde_json::Value) -> String {
+    const ANY: &str = r#"(true|false|null|-?[0-9]+(\.[0-9]+)?|"[^"]*"|\[.*\]|\{.*\})"#;
+
+    if let Some(values) = schema.get("enum").and_then(|v| v.as_array()) {
+        let alternatives: Vec<String> = values
+            .iter()
+            .map(|v| regex_escape(&v.to_string()))
+            .collect();
+        return format!("({})", alternatives.join("|"));
+    }
+
+    match schema.get("type").and_then(|t| t.as_str()) {
+        Some("string") => r#""[^"]*""#.to_string(),
+        Some("number") | Some("integer") => r"-?[0-9]+(\.[0-9]+)?".to_string(),
+        Some("boolean") => "(true|false)".to_string(),
+        Some("null") => "null".to_string(),
+        Some("object") => {
+            let properties = schema.get("properties").and_then(|p| p.as_object());
+            let required: HashSet<&str> = schema
+                .get("required")
+                .and_then(|r| r.as_array())
+                .map(|r| r.iter().filter_map(|v| v.as_str()).collect())
+                .unwrap_or_default();
+
+            let Some(properties) = properties else {
+                return ANY.to_string();
+            };
+
+            let mut required_fields = Vec::new();
+            let mut optional_fields = Vec::new();
+            for (key, value) in properties {
+                let field = format!(r#""{}":{}"#, key, json_schema_to_regex(value));
+                if required.contains(key.as_str()) {
+                    required_fields.push(field);
+                } else {
+                    optional_fields.push(field);
+                }
+            }
+
+            let mut tail = String::new();
+            for (index, field) in optional_fields.iter().enumerate().rev() {
+                let is_leading = required_fields.is_empty() && index == 0;
+                let separator = if is_leading { "" } else { "," };
+                tail = format!("({}{}{})?", separator, field, tail);
+            }
+
+            format!(r"\{{{}{}\}}", required_fields.join(","), tail)
+        }
+        _ => ANY.to_string(),
+    }
+}
+
+fn regex_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fully_matches(pattern: &str, input: &str) -> bool {
+        let dfa = Dfa::compile(pattern);
+        match dfa.advance(dfa.start(), input) {
+            Some(state) => dfa.is_accepting(state),
+            None => false,
+        }
+    }
+
+    #[test]
+    fn literal_concatenation() {
+        assert!(fully_matches("abc", "abc"));
+        assert!(!fully_matches("abc", "abd"));
+        assert!(!fully_matches("abc", "ab"));
+    }
+
+    #[test]
+    fn alternation_and_quantifiers() {
+        assert!(fully_matches("a(b|c)*d", "ad"));
+        assert!(fully_matches("a(b|c)*d", "abccbd"));
+        assert!(!fully_matches("a(b|c)*d", "ae"));
+        assert!(fully_matches("ab?c", "ac"));
+        assert!(fully_matches("ab?c", "abc"));
+        assert!(fully_matches("ab+c", "abbbc"));
+        assert!(!fully_matches("ab+c", "ac"));
+    }
+
+    #[test]
+    fn nested_alternation_inside_groups() {
+        assert!(fully_matches("(a|(b|c))d", "ad"));
+        assert!(fully_matches("(a|(b|c))d", "bd"));
+        assert!(fully_matches("(a|(b|c))d", "cd"));
+        assert!(!fully_matches("(a|(b|c))d", "dd"));
+    }
+
+    #[test]
+    fn char_class_ranges_and_trailing_dash() {
+        assert!(fully_matches("[a-c]+", "abcabc"));
+        assert!(!fully_matches("[a-c]+", "abcd"));
+        // A `-` directly before the closing `]` is a literal, not a dangling range.
+        assert!(fully_matches("[a-]+", "a--a"));
+        assert!(!fully_matches("[a-]+", "b"));
+    }
+
+    #[test]
+    fn negated_char_class() {
+        assert!(fully_matches("[^abc]", "d"));
+        assert!(!fully_matches("[^abc]", "a"));
+    }
+
+    #[test]
+    fn char_class_with_non_ascii_member_matches_its_full_utf8_sequence() {
+        // "é" is two UTF-8 bytes (0xC3 0xA9); a class containing it must match that whole
+        // sequence, not a truncated single byte.
+        assert!(fully_matches("[aé]", "a"));
+        assert!(fully_matches("[aé]", "é"));
+        assert!(!fully_matches("[aé]", "b"));
+        assert!(!fully_matches("[aé]", "Ã")); // shares the leading byte 0xC3 with "é", not a match
+    }
+
+    #[test]
+    fn multi_byte_utf8_literal_matches_as_a_sequence_not_a_byte_union() {
+        assert!(fully_matches("é+", "éé"));
+        assert!(!fully_matches("é+", "e"));
+        // "é" and "Ã" share a leading byte (0xC3) but differ on the second. If the literal were
+        // compiled as a union over bytes instead of an ordered sequence, both would wrongly match.
+        assert!(fully_matches("é", "é"));
+        assert!(!fully_matches("é", "Ã"));
+    }
+
+    #[test]
+    fn advance_dies_on_any_invalid_byte_not_just_the_last() {
+        let dfa = Dfa::compile("ab");
+        let start = dfa.start();
+        // "a" is a valid (non-accepting) prefix, so the automaton is still alive.
+        let mid = dfa.advance(start, "a").expect("'a' is a valid prefix");
+        assert!(!dfa.is_accepting(mid));
+        // "ax" dies on the second byte -- the whole token is rejected, not just its tail.
+        assert!(dfa.advance(start, "ax").is_none());
+    }
+
+    #[test]
+    fn token_index_excludes_tokens_that_die_partway_through() {
+        let dfa = Dfa::compile("ab");
+        let vocab = vec![
+            (0, "ab".to_string()),
+            (1, "ax".to_string()),
+            (2, "a".to_string()),
+        ];
+        let index = dfa.token_index(&vocab);
+        let start_allowed = &index[dfa.start()];
+        assert!(start_allowed.contains(&0));
+        assert!(
+            start_allowed.contains(&2),
+            "\"a\" is a valid, non-terminal prefix"
+        );
+        assert!(!start_allowed.contains(&1), "\"ax\" dies partway through");
+    }
+
+    #[test]
+    fn json_schema_required_field_is_mandatory_optional_field_is_not() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "number"},
+            },
+            "required": ["name"],
+        });
+        let pattern = json_schema_to_regex(&schema);
+        assert!(fully_matches(&pattern, r#"{"name":"a"}"#));
+        assert!(fully_matches(&pattern, r#"{"name":"a","age":1}"#));
+        assert!(!fully_matches(&pattern, r#"{"age":1}"#));
+    }
+
+    #[test]
+    fn json_schema_with_no_required_fields_allows_the_empty_object() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+            },
+        });
+        let pattern = json_schema_to_regex(&schema);
+        assert!(fully_matches(&pattern, "{}"));
+        assert!(fully_matches(&pattern, r#"{"name":"a"}"#));
+    }
+}